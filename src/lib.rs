@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io::{Cursor, Write};
 use zip::write::{FileOptions};
@@ -5,55 +6,280 @@ use slugify::slugify;
 use chrono::{Local};
 use uuid::Uuid;
 
+/// Controls whether chapter/metadata text is escaped before being
+/// interpolated into XHTML, or written through untouched.
+pub enum ContentMode {
+  /// Escape XML special characters (the default for plain-text content).
+  PlainText,
+  /// Write the text through as-is, for callers who embed their own markup.
+  RawHtml,
+}
+
+/// Selects which OPF package conventions `archive()` emits for
+/// `Info.version`: EPUB 2.0.1 or EPUB 3.0.
+enum EpubVersion {
+  Epub2,
+  Epub3,
+}
+
+impl EpubVersion {
+  fn from_version(version: i8) -> EpubVersion {
+    match version {
+      2 => EpubVersion::Epub2,
+      _ => EpubVersion::Epub3,
+    }
+  }
+}
+
+/// A MARC relator role (https://www.loc.gov/marc/relators/) for a
+/// contributor. Only the roles this crate renders are modelled.
+pub enum ContributorRole {
+  Author,
+  Editor,
+  Translator,
+  Illustrator,
+}
+
+impl ContributorRole {
+  fn marc_code(&self) -> &'static str {
+    match self {
+      ContributorRole::Author => "aut",
+      ContributorRole::Editor => "edt",
+      ContributorRole::Translator => "trl",
+      ContributorRole::Illustrator => "ill",
+    }
+  }
+}
+
+/// A person credited on the book, tagged with their MARC relator role.
+pub struct Contributor {
+  pub name: String,
+  pub role: ContributorRole,
+}
+
+/// The scheme an `Identifier`'s value is drawn from.
+pub enum IdentifierScheme {
+  Isbn,
+  Doi,
+  Uuid,
+}
+
+impl IdentifierScheme {
+  fn label(&self) -> &'static str {
+    match self {
+      IdentifierScheme::Isbn => "ISBN",
+      IdentifierScheme::Doi => "DOI",
+      IdentifierScheme::Uuid => "UUID",
+    }
+  }
+}
+
+/// A book identifier (ISBN, DOI, ...) alongside the scheme it is drawn from.
+pub struct Identifier {
+  pub scheme: IdentifierScheme,
+  pub value: String,
+}
+
 pub struct Info {
   pub title: String,
+  pub subtitle: Option<String>,
   pub description: String,
   pub publisher: String,
-  pub author: String,
+  pub contributors: Vec<Contributor>,
+  pub identifiers: Vec<Identifier>,
   pub toc_title: String,
   pub lang: String,
   pub fonts: Vec<String>,
   pub css: Option<String>,
   pub version: i8,
+  pub content_mode: ContentMode,
+  pub cover: Option<CoverImage>,
+}
+
+/// Escapes the characters that are invalid inside XML text/attribute
+/// content: `&`, `<`, `>`, `"`, `'`, and a literal non-breaking space.
+fn escape_xml(raw: &str) -> String {
+  raw
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&#39;")
+    .replace('\u{00A0}', "&#160;")
+}
+
+/// A binary file (image, font, ...) embedded in the EPUB and tracked in
+/// the OPF manifest.
+pub struct Resource {
+  pub href: String,
+  pub media_type: String,
+  pub data: Vec<u8>,
+}
+
+/// Infers a manifest `media-type` from a file name's extension.
+fn infer_media_type(file_name: &str) -> &'static str {
+  match file_name.rsplit('.').next().unwrap_or("").to_lowercase().as_str() {
+    "jpg" | "jpeg" => "image/jpeg",
+    "png" => "image/png",
+    "gif" => "image/gif",
+    "svg" => "image/svg+xml",
+    "webp" => "image/webp",
+    _ => "application/octet-stream",
+  }
+}
+
+/// Infers a manifest `media-type` for a font file from its extension.
+fn infer_font_media_type(file_name: &str) -> &'static str {
+  match file_name.rsplit('.').next().unwrap_or("").to_lowercase().as_str() {
+    "ttf" | "otf" => "application/vnd.ms-opentype",
+    "woff" => "application/font-woff",
+    "woff2" => "font/woff2",
+    _ => "application/octet-stream",
+  }
+}
+
+/// The href a font source path is stored under inside `OEBPS/fonts/`.
+fn font_href(path: &str) -> String {
+  let file_name = std::path::Path::new(path)
+    .file_name()
+    .and_then(|s| s.to_str())
+    .unwrap_or(path);
+
+  format!("fonts/{}", file_name)
+}
+
+/// A cover image, supplied either as a path to read at archive time or as
+/// already-loaded bytes (mirroring `add_resource`'s bytes-based API).
+pub enum CoverImage {
+  Path(String),
+  Bytes { file_name: String, data: Vec<u8> },
+}
+
+impl CoverImage {
+  fn file_name(&self) -> &str {
+    match self {
+      CoverImage::Path(path) => std::path::Path::new(path)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or(path),
+      CoverImage::Bytes { file_name, .. } => file_name,
+    }
+  }
+}
+
+/// The href a cover image is stored under inside `OEBPS/images/`.
+fn cover_href(cover: &CoverImage) -> String {
+  format!("images/{}", cover.file_name())
+}
+
+/// A chapter (or part/sub-chapter) in the book. `children` lets a chapter
+/// nest sub-sections so the table of contents can be more than one level
+/// deep; each chapter, nested or not, gets its own XHTML page.
+pub struct Chapter {
+  pub title: String,
+  pub content: Vec<String>,
+  pub children: Vec<Chapter>,
+}
+
+/// Depth-first pre-order flattening of a chapter tree, used everywhere
+/// every page needs visiting regardless of nesting (manifest, spine, ...).
+fn flatten_chapters(chapters: &[Chapter]) -> Vec<&Chapter> {
+  let mut flat = vec![];
+
+  for chapter in chapters {
+    flat.push(chapter);
+    flat.extend(flatten_chapters(&chapter.children));
+  }
+
+  flat
+}
+
+/// The deepest level of nesting in a chapter tree (a flat list is `1`).
+fn chapters_depth(chapters: &[Chapter]) -> usize {
+  chapters
+    .iter()
+    .map(|chapter| 1 + chapters_depth(&chapter.children))
+    .max()
+    .unwrap_or(0)
+}
+
+/// Assigns every chapter in the tree a unique href/id slug. Nesting makes
+/// same-titled siblings under different parents more likely, so slugs
+/// that collide with an earlier chapter's get an index suffix appended.
+fn chapter_slugs(chapters: &[Chapter]) -> HashMap<*const Chapter, String> {
+  let mut seen: HashMap<String, usize> = HashMap::new();
+  let mut slugs = HashMap::new();
+
+  for chapter in flatten_chapters(chapters) {
+    let base = slugify!(&chapter.title, separator = "_");
+    let count = seen.entry(base.clone()).or_insert(0);
+
+    let slug = if *count == 0 {
+      base
+    } else {
+      format!("{}_{}", base, count)
+    };
+
+    *count += 1;
+    slugs.insert(chapter as *const Chapter, slug);
+  }
+
+  slugs
 }
 
 pub struct EPUB {
   info: Info,
-  chapters: Vec<Vec<String>>
+  chapters: Vec<Chapter>,
+  resources: Vec<Resource>,
 }
 
 impl EPUB {
-  pub fn new(info: Info, chapters: Vec<Vec<String>>) -> EPUB {
+  pub fn new(info: Info, chapters: Vec<Chapter>) -> EPUB {
     EPUB {
       info,
-      chapters
+      chapters,
+      resources: vec![],
     }
   }
 
-  pub fn run(&mut self) {
-    let archive_result = self.archive();
+  /// Embeds a binary resource (an image, typically) under `OEBPS/images/`
+  /// and tracks it so it gets a manifest `<item>`. Returns the href the
+  /// resource was stored under, for referencing from chapter content
+  /// (e.g. `<img src="images/cover.jpg" />`).
+  pub fn add_resource(&mut self, file_name: &str, data: Vec<u8>) -> String {
+    let href = format!("images/{}", file_name);
+    let media_type = infer_media_type(file_name).to_string();
 
-    let archive: Vec<u8> = match archive_result {
-      Ok(vec) => vec,
-      Err(err) => panic!("{}", err)
-    };
+    self.resources.push(Resource { href: href.clone(), media_type, data });
 
-    self.write(archive);
+    href
   }
 
-  fn write_chapters(&self) -> Vec<(&String, String)> {
-    let mut _chapters = vec![];
+  /// Escapes `raw` according to `self.info.content_mode`, leaving it
+  /// untouched in `ContentMode::RawHtml`.
+  fn escape(&self, raw: &str) -> String {
+    match self.info.content_mode {
+      ContentMode::PlainText => escape_xml(raw),
+      ContentMode::RawHtml => raw.to_string(),
+    }
+  }
 
-    for chapter in &self.chapters {
-      let title = &chapter[0];
-      let content = chapter
-        .iter()
-        .skip(1)
-        .map(|raw| format!("<p>{}</p>", raw))
-        .reduce(|cur: String, nxt: String| cur + &nxt + "\n")
-        .unwrap();
-
-      let template = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+  /// Builds the EPUB and writes it to `output_path`.
+  pub fn run(&mut self, output_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let archive = self.archive()?;
+
+    self.write(output_path, archive)
+  }
+
+  fn write_chapter(&self, chapter: &Chapter, slugs: &HashMap<*const Chapter, String>, out: &mut Vec<(String, String)>) {
+    let title = self.escape(&chapter.title);
+    let content = chapter.content
+      .iter()
+      .map(|raw| format!("<p>{}</p>", self.escape(raw)))
+      .collect::<Vec<String>>()
+      .join("\n");
+
+    let template = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
 <!DOCTYPE html>
 <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{lang}\" lang=\"{lang}\">
   <head>
@@ -65,70 +291,341 @@ impl EPUB {
     <h1>{title}</h1>
     {content}
   </body>
-</html>", title=self.info.title, lang=self.info.lang, content=content);
+</html>", title=title, lang=self.escape(&self.info.lang), content=content);
+
+    let slug = slugs.get(&(chapter as *const Chapter)).expect("every chapter has a slug");
+
+    out.push((slug.clone(), template));
 
-      _chapters.push((title, template));
-    } 
+    for child in &chapter.children {
+      self.write_chapter(child, slugs, out);
+    }
+  }
+
+  fn write_chapters(&self) -> Vec<(String, String)> {
+    let slugs = chapter_slugs(&self.chapters);
+    let mut chapters = vec![];
+
+    for chapter in &self.chapters {
+      self.write_chapter(chapter, &slugs, &mut chapters);
+    }
 
-    _chapters
+    chapters
   }
 
   fn manifest(&self) -> String {
-    let xhtml_targets: String = self.chapters
+    let slugs = chapter_slugs(&self.chapters);
+    let xhtml_targets: String = flatten_chapters(&self.chapters)
       .iter()
-      .map(|s| format!("<item id=\"{}\" href=\"{}.xhtml\" media-type=\"application/xhtml+xml\" />", slugify!(&s[0]), slugify!(&s[0], separator = "_")))
-      .reduce(|cur: String, nxt: String| cur + &nxt + "\n")
-      .unwrap();
+      .map(|c| {
+        let slug = slugs.get(&(*c as *const Chapter)).expect("every chapter has a slug");
+        format!("<item id=\"{slug}\" href=\"{slug}.xhtml\" media-type=\"application/xhtml+xml\" />", slug=slug)
+      })
+      .collect::<Vec<String>>()
+      .join("\n");
+
+    let resource_targets: String = self.resources
+      .iter()
+      .map(|r| format!("<item id=\"{}\" href=\"{}\" media-type=\"{}\" />", slugify!(&r.href, separator = "_"), escape_xml(&r.href), r.media_type))
+      .collect::<Vec<String>>()
+      .join("\n");
+
+    let font_targets: String = self.info.fonts
+      .iter()
+      .map(|path| {
+        let href = font_href(path);
+        format!("<item id=\"{}\" href=\"{}\" media-type=\"{}\" />", slugify!(&href, separator = "_"), escape_xml(&href), infer_font_media_type(&href))
+      })
+      .collect::<Vec<String>>()
+      .join("\n");
+
+    let cover_targets: String = match &self.info.cover {
+      Some(cover) => {
+        let href = escape_xml(&cover_href(cover));
+        let cover_image_properties = match EpubVersion::from_version(self.info.version) {
+          EpubVersion::Epub2 => "",
+          EpubVersion::Epub3 => " properties=\"cover-image\"",
+        };
+
+        format!("<item id=\"cover-image\" href=\"{href}\" media-type=\"{media_type}\"{properties} />
+<item id=\"cover\" href=\"cover.xhtml\" media-type=\"application/xhtml+xml\" />", href=href, media_type=infer_media_type(cover.file_name()), properties=cover_image_properties)
+      },
+      None => String::new(),
+    };
+
+    let toc_item = match EpubVersion::from_version(self.info.version) {
+      EpubVersion::Epub2 => "<item id=\"toc\" href=\"toc.xhtml\" media-type=\"application/xhtml+xml\" />",
+      EpubVersion::Epub3 => "<item id=\"toc\" href=\"toc.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\" />",
+    };
 
     format!("<item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\" />
-<item id=\"toc\" href=\"toc.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\" />
+{}
 <item id=\"css\" href=\"styles.css\" media-type=\"text/css\" />
-{}", xhtml_targets)
+{}
+{}
+{}
+{}", toc_item, xhtml_targets, resource_targets, font_targets, cover_targets)
   }
 
-  fn toc_xhtml(&self) -> String {
-    let mut li = vec![];
+  fn spine(&self) -> String {
+    let mut itemrefs = vec![];
 
-    for chapter in self.chapters.iter() {
-      let title = &chapter[0];
-      let src = format!("{}.xhtml", slugify!(title, separator = "_"));
+    if self.info.cover.is_some() {
+      itemrefs.push(String::from("<itemref idref=\"cover\" linear=\"no\" />"));
+    }
 
-      li.push(format!("<li class=\"table-of-content\">
-      <a href=\"{}\">{}</a>
-    </li>", src, title));
+    let slugs = chapter_slugs(&self.chapters);
+
+    for chapter in flatten_chapters(&self.chapters) {
+      let slug = slugs.get(&(chapter as *const Chapter)).expect("every chapter has a slug");
+      itemrefs.push(format!("<itemref idref=\"{}\" />", slug));
     }
 
-    li
+    itemrefs.join("\n")
+  }
+
+  /// The names of all contributors tagged with the `Author` role, for
+  /// display contexts (e.g. `toc.ncx`'s `docAuthor`) that only want a
+  /// plain-text byline rather than the full contributor list.
+  fn authors(&self) -> String {
+    self.info.contributors
       .iter()
-      .map(|s| s.to_string())
-      .reduce(|cur: String, nxt: String| cur + &nxt + "\n")
-      .unwrap()
+      .filter(|contributor| matches!(contributor.role, ContributorRole::Author))
+      .map(|contributor| self.escape(&contributor.name))
+      .collect::<Vec<String>>()
+      .join(", ")
   }
 
-  fn toc_ncx(&self) -> String {
-    let mut li = vec![];
+  /// `<dc:title>`/`<meta refines property="title-type">` pair for the
+  /// main title, plus a second pair for the subtitle when present.
+  fn title_meta(&self) -> String {
+    let main = format!("<dc:title id=\"title\">{}</dc:title>
+<meta refines=\"#title\" property=\"title-type\">main</meta>", self.escape(&self.info.title));
+
+    match &self.info.subtitle {
+      Some(subtitle) => format!("{main}
+<dc:title id=\"subtitle\">{subtitle}</dc:title>
+<meta refines=\"#subtitle\" property=\"title-type\">subtitle</meta>", main=main, subtitle=self.escape(subtitle)),
+      None => main,
+    }
+  }
 
-    for (index, chapter) in self.chapters.iter().enumerate() {
-      let next = index + 1;
-      let content_id = format!("content_{}_item_{}", index, index);
-      let title = format!("{}. {}", next, &chapter[0]);
-      let src = format!("{}.xhtml", slugify!(&chapter[0], separator = "_"));
+  /// `<dc:creator>`/`<dc:contributor>` elements with MARC relator role
+  /// refinements, one pair per entry in `Info.contributors`.
+  fn creators(&self) -> String {
+    self.info.contributors
+      .iter()
+      .enumerate()
+      .map(|(index, contributor)| {
+        let id = format!("contributor-{}", index);
+        let tag = match contributor.role {
+          ContributorRole::Author => "dc:creator",
+          _ => "dc:contributor",
+        };
+
+        format!("<{tag} id=\"{id}\">{name}</{tag}>
+<meta refines=\"#{id}\" property=\"role\" scheme=\"marc:relators\">{role}</meta>", tag=tag, id=id, name=self.escape(&contributor.name), role=contributor.role.marc_code())
+      })
+      .collect::<Vec<String>>()
+      .join("\n")
+  }
+
+  /// EPUB 2.0.1 title: OPF 2.0 has no `meta refines` title-type mechanism,
+  /// so the subtitle (when present) is folded into a single `dc:title`.
+  fn title_v2(&self) -> String {
+    match &self.info.subtitle {
+      Some(subtitle) => format!("{}: {}", self.escape(&self.info.title), self.escape(subtitle)),
+      None => self.escape(&self.info.title),
+    }
+  }
+
+  /// `<dc:creator>`/`<dc:contributor>` elements using OPF 2.0's
+  /// `opf:role`/`opf:file-as` attributes instead of `meta refines`.
+  fn creators_v2(&self) -> String {
+    self.info.contributors
+      .iter()
+      .map(|contributor| {
+        let tag = match contributor.role {
+          ContributorRole::Author => "dc:creator",
+          _ => "dc:contributor",
+        };
+
+        format!("<{tag} opf:file-as=\"{name}\" opf:role=\"{role}\">{name}</{tag}>", tag=tag, name=self.escape(&contributor.name), role=contributor.role.marc_code())
+      })
+      .collect::<Vec<String>>()
+      .join("\n")
+  }
+
+  /// `<dc:identifier>`/`<meta refines property="identifier-type">` pairs,
+  /// one per entry in `Info.identifiers`. The first identifier is always
+  /// given `id="BookId"` so it can back `unique-identifier`. Falls back to
+  /// a freshly generated UUID when no identifiers were configured.
+  fn identifiers(&self, fallback_uuid: Uuid) -> String {
+    if self.info.identifiers.is_empty() {
+      return format!("<dc:identifier id=\"BookId\">{uuid}</dc:identifier>
+<meta refines=\"#BookId\" property=\"identifier-type\">UUID</meta>", uuid=fallback_uuid);
+    }
 
-      li.push(format!("<navPoint id=\"{}\" playOrder=\"{}\" class=\"chapter\">
+    self.info.identifiers
+      .iter()
+      .enumerate()
+      .map(|(index, identifier)| {
+        let id = if index == 0 { String::from("BookId") } else { format!("identifier-{}", index) };
+
+        format!("<dc:identifier id=\"{id}\">{value}</dc:identifier>
+<meta refines=\"#{id}\" property=\"identifier-type\">{scheme}</meta>", id=id, value=self.escape(&identifier.value), scheme=identifier.scheme.label())
+      })
+      .collect::<Vec<String>>()
+      .join("\n")
+  }
+
+  /// EPUB 2.0.1 identifiers: OPF 2.0 has no `meta refines` type mechanism,
+  /// so the scheme is carried on an `opf:scheme` attribute instead.
+  fn identifiers_v2(&self, fallback_uuid: Uuid) -> String {
+    if self.info.identifiers.is_empty() {
+      return format!("<dc:identifier id=\"BookId\" opf:scheme=\"UUID\">{}</dc:identifier>", fallback_uuid);
+    }
+
+    self.info.identifiers
+      .iter()
+      .enumerate()
+      .map(|(index, identifier)| {
+        let id = if index == 0 { String::from("BookId") } else { format!("identifier-{}", index) };
+
+        format!("<dc:identifier id=\"{id}\" opf:scheme=\"{scheme}\">{value}</dc:identifier>", id=id, scheme=identifier.scheme.label(), value=self.escape(&identifier.value))
+      })
+      .collect::<Vec<String>>()
+      .join("\n")
+  }
+
+  fn toc_xhtml_list(&self, chapters: &[Chapter], slugs: &HashMap<*const Chapter, String>) -> String {
+    chapters
+      .iter()
+      .map(|chapter| {
+        let slug = slugs.get(&(chapter as *const Chapter)).expect("every chapter has a slug");
+        let src = format!("{}.xhtml", slug);
+        let children = self.toc_xhtml_list(&chapter.children, slugs);
+        let nested = if children.is_empty() {
+          String::new()
+        } else {
+          format!("<ol>\n{}\n</ol>", children)
+        };
+
+        format!("<li class=\"table-of-content\">
+      <a href=\"{}\">{}</a>
+      {}
+    </li>", src, self.escape(&chapter.title), nested)
+      })
+      .collect::<Vec<String>>()
+      .join("\n")
+  }
+
+  fn toc_xhtml(&self) -> String {
+    let slugs = chapter_slugs(&self.chapters);
+    self.toc_xhtml_list(&self.chapters, &slugs)
+  }
+
+  fn toc_ncx_list(&self, chapters: &[Chapter], slugs: &HashMap<*const Chapter, String>, play_order: &mut usize) -> String {
+    chapters
+      .iter()
+      .map(|chapter| {
+        *play_order += 1;
+        let order = *play_order;
+        let content_id = format!("content_{}_item_{}", order, order);
+        let title = format!("{}. {}", order, self.escape(&chapter.title));
+        let slug = slugs.get(&(chapter as *const Chapter)).expect("every chapter has a slug");
+        let src = format!("{}.xhtml", slug);
+        let children = self.toc_ncx_list(&chapter.children, slugs, play_order);
+
+        format!("<navPoint id=\"{}\" playOrder=\"{}\" class=\"chapter\">
   <navLabel>
     <text>{}</text>
   </navLabel>
   <content src=\"{}\"/>
-</navPoint>", content_id, next, title, src));
-    }
+  {}
+</navPoint>", content_id, order, title, src, children)
+      })
+      .collect::<Vec<String>>()
+      .join("\n")
+  }
 
-    li
-      .iter()
-      .map(|s| s.to_string())
-      .reduce(|cur: String, nxt: String| cur + &nxt + "\n")
-      .unwrap()
+  fn toc_ncx(&self) -> String {
+    let slugs = chapter_slugs(&self.chapters);
+    let mut play_order = 0usize;
+    self.toc_ncx_list(&self.chapters, &slugs, &mut play_order)
   }
   
+  /// EPUB 3.0 `content.opf`: `dcterms` meta refinements, ibooks prefix,
+  /// `properties="nav"` navigation document.
+  fn content_opf_v3(&self, uuid: Uuid) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+  <package
+    xmlns=\"http://www.idpf.org/2007/opf\"
+    version=\"3.0\"
+    unique-identifier=\"BookId\"
+    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"
+    xmlns:dcterms=\"http://purl.org/dc/terms/\"
+    xml:lang=\"{lang}\"
+    xmlns:media=\"http://www.idpf.org/epub/vocab/overlays/#\"
+    prefix=\"ibooks: http://vocabulary.itunes.apple.com/rdf/ibooks/vocabulary-extensions-1.0/\">
+
+  <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">
+    {identifiers}
+    {title}
+    <dc:language>{lang}</dc:language>
+    <meta property=\"dcterms:language\" id=\"meta-language\">{lang}</meta>
+    <meta property=\"dcterms:modified\">{date}</meta>
+    {creators}
+    <meta property=\"dcterms:publisher\">{publisher}</meta>
+    <dc:publisher>{publisher}</dc:publisher>
+    <meta property=\"dcterms:date\">{date}</meta>
+    <dc:date>{date}</dc:date>
+    <meta property=\"dcterms:rights\">All rights reserved</meta>
+    <dc:rights>Copyright &#x00A9; {year} by {publisher}</dc:rights>
+    <meta name=\"generator\" content=\"epub-gen-rs\" />
+    <meta property=\"ibooks:specified-fonts\">{specified_fonts}</meta>
+    {cover_meta}
+  </metadata>
+  <manifest>
+    {manifest}
+  </manifest>
+  <spine toc=\"ncx\">
+    {spine}
+  </spine>
+</package>", identifiers=self.identifiers(uuid), title=self.title_meta(), lang=self.escape(&self.info.lang), date=Local::now(), year=Local::now().format("%Y"), creators=self.creators(), publisher=self.escape(&self.info.publisher), specified_fonts=!self.info.fonts.is_empty(), cover_meta=if self.info.cover.is_some() { "<meta name=\"cover\" content=\"cover-image\"/>" } else { "" }, manifest=self.manifest(), spine=self.spine())
+  }
+
+  /// EPUB 2.0.1 `content.opf`: plain Dublin Core metadata, no `dcterms`
+  /// refinements, `toc.ncx` as the sole navigation document.
+  fn content_opf_v2(&self, uuid: Uuid) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+  <package
+    xmlns=\"http://www.idpf.org/2007/opf\"
+    version=\"2.0\"
+    unique-identifier=\"BookId\"
+    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"
+    xml:lang=\"{lang}\">
+
+  <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">
+    {identifiers}
+    <dc:title>{title}</dc:title>
+    <dc:language>{lang}</dc:language>
+    {creators}
+    <dc:publisher>{publisher}</dc:publisher>
+    <dc:date>{date}</dc:date>
+    <dc:rights>Copyright &#x00A9; {year} by {publisher}</dc:rights>
+    <meta name=\"generator\" content=\"epub-gen-rs\" />
+    {cover_meta}
+  </metadata>
+  <manifest>
+    {manifest}
+  </manifest>
+  <spine toc=\"ncx\">
+    {spine}
+  </spine>
+</package>", identifiers=self.identifiers_v2(uuid), lang=self.escape(&self.info.lang), title=self.title_v2(), date=Local::now(), year=Local::now().format("%Y"), creators=self.creators_v2(), publisher=self.escape(&self.info.publisher), cover_meta=if self.info.cover.is_some() { "<meta name=\"cover\" content=\"cover-image\" />" } else { "" }, manifest=self.manifest(), spine=self.spine())
+  }
+
   pub fn archive(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut archive = Cursor::new(Vec::new());
 
@@ -161,41 +658,10 @@ impl EPUB {
     zip.start_file("OEBPS/content.opf", Default::default())?;
     // uuid for unique-identifier
     let unique_identifier: Uuid = Uuid::new_v4();
-    let content: String = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
-  <package 
-    xmlns=\"http://www.idpf.org/2007/opf\"
-    version=\"3.0\"
-    unique-identifier=\"BookId\"
-    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"
-    xmlns:dcterms=\"http://purl.org/dc/terms/\"
-    xml:lang=\"{lang}\"
-    xmlns:media=\"http://www.idpf.org/epub/vocab/overlays/#\"
-    prefix=\"ibooks: http://vocabulary.itunes.apple.com/rdf/ibooks/vocabulary-extensions-1.0/\">
-
-  <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">
-    <dc:identifier id=\"BookId\">{uuid}</dc:identifier>
-    <meta refines=\"#BookId\" property=\"identifier-type\" scheme=\"onix:codelist5\">22</meta>
-    <meta property=\"dcterms:identifier\" id=\"meta-identifier\">BookId</meta>
-    <dc:title>{title}</dc:title>
-    <meta property=\"dcterms:title\" id=\"meta-title\">{title}</meta>
-    <dc:language>{lang}</dc:language>
-    <meta property=\"dcterms:language\" id=\"meta-language\">{lang}</meta>
-    <meta property=\"dcterms:modified\">{date}</meta>
-    <dc:creator id=\"creator\">{author}</dc:creator>
-    <meta refines=\"#creator\" property=\"file-as\">{author}</meta>
-    <meta property=\"dcterms:publisher\">{publisher}</meta>
-    <dc:publisher>{publisher}</dc:publisher>
-    <meta property=\"dcterms:date\">{date}</meta>
-    <dc:date>{date}</dc:date>
-    <meta property=\"dcterms:rights\">All rights reserved</meta>
-    <dc:rights>Copyright &#x00A9; 2023 by {publisher}</dc:rights>
-    <meta name=\"generator\" content=\"epub-gen-rs\" />
-    <meta property=\"ibooks:specified-fonts\">false</meta>
-  </metadata>
-  <manifest>
-    {manifest}
-  </manifest>
-</package>", uuid=unique_identifier, author=self.info.author, lang=self.info.lang, title=self.info.title, date=Local::now(), publisher=self.info.publisher, manifest=self.manifest());
+    let content: String = match EpubVersion::from_version(self.info.version) {
+      EpubVersion::Epub2 => self.content_opf_v2(unique_identifier),
+      EpubVersion::Epub3 => self.content_opf_v3(unique_identifier),
+    };
 
     zip.write_all(content.as_bytes())?;
 
@@ -207,7 +673,7 @@ impl EPUB {
   <head>
     <meta name=\"dtb:uid\" content=\"{uuid}\" />
     <meta name=\"dtb:generator\" content=\"epub-gen-rs\"/>
-    <meta name=\"dtb:depth\" content=\"1\"/>
+    <meta name=\"dtb:depth\" content=\"{depth}\"/>
     <meta name=\"dtb:totalPageCount\" content=\"0\"/>
     <meta name=\"dtb:maxPageNumber\" content=\"0\"/>
   </head>
@@ -226,7 +692,7 @@ impl EPUB {
     </navPoint>
     {toc}
   </navMap>
-</ncx>", uuid=unique_identifier, author=self.info.author, title=self.info.title, toc_title=self.info.toc_title, toc=self.toc_ncx());
+</ncx>", uuid=unique_identifier, depth=chapters_depth(&self.chapters).max(1), author=self.authors(), title=self.escape(&self.info.title), toc_title=self.escape(&self.info.toc_title), toc=self.toc_ncx());
 
     zip.write_all(toc.as_bytes())?;
 
@@ -248,18 +714,63 @@ impl EPUB {
     </ol>
   </nav>
 </body>
-</html>", lang=self.info.lang, title=self.info.title, toc=self.toc_xhtml());
+</html>", lang=self.escape(&self.info.lang), title=self.escape(&self.info.title), toc=self.toc_xhtml());
 
     zip.write_all(toc.as_bytes())?;
 
+    // cover
+    if let Some(cover) = &self.info.cover {
+      let data = match cover {
+        CoverImage::Path(path) => fs::read(path)?,
+        CoverImage::Bytes { data, .. } => data.clone(),
+      };
+      let href = cover_href(cover);
+
+      zip.start_file(format!("OEBPS/{}", href), stored)?;
+      zip.write_all(&data)?;
+
+      let cover_xhtml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<!DOCTYPE html>
+<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{lang}\" lang=\"{lang}\">
+  <head>
+    <meta charset=\"UTF-8\" />
+    <title>{title}</title>
+    <link rel=\"stylesheet\" type=\"text/css\" href=\"styles.css\" />
+  </head>
+  <body>
+    <img src=\"{href}\" alt=\"{title}\" />
+  </body>
+</html>", lang=self.escape(&self.info.lang), title=self.escape(&self.info.title), href=escape_xml(&href));
+
+      zip.start_file("OEBPS/cover.xhtml", stored)?;
+      zip.write_all(cover_xhtml.as_bytes())?;
+    }
+
     // XHTML's
-    for (title, raw) in chapters.iter() {
-      zip.start_file(format!("OEBPS/{}.xhtml", slugify!(title, separator = "_")), stored)?;
+    for (slug, raw) in chapters.iter() {
+      zip.start_file(format!("OEBPS/{}.xhtml", slug), stored)?;
       zip.write_all(raw.as_bytes())?;
     }
 
+    // fonts
+    let mut font_faces = String::new();
+    for path in &self.info.fonts {
+      let data = fs::read(path)?;
+      let href = font_href(path);
+      let family = std::path::Path::new(path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("font");
+
+      font_faces.push_str(&format!("@font-face {{ font-family: \"{family}\"; src: url(\"{href}\"); }}\n", family=family, href=href));
+
+      zip.start_file(format!("OEBPS/{}", href), stored)?;
+      zip.write_all(&data)?;
+    }
+
     // CSS
     zip.start_file("OEBPS/styles.css", Default::default())?;
+    zip.write_all(font_faces.as_bytes())?;
     match &self.info.css {
       Some(css) => {
         zip.write_all(css.as_bytes())?;
@@ -269,11 +780,22 @@ impl EPUB {
       }
     }
 
-    Ok(zip.finish().unwrap().clone().into_inner())
+    // resources (images, ...)
+    for resource in &self.resources {
+      zip.start_file(format!("OEBPS/{}", resource.href), stored)?;
+      zip.write_all(&resource.data)?;
+    }
+
+    let written = zip.finish()?;
+
+    Ok(written.clone().into_inner())
   }
 
-  pub fn write(&mut self, data: Vec<u8>)  {
-    fs::write(&format!("{}.epub", &self.info.title), data).ok();
+  /// Writes the archived EPUB bytes to `output_path`.
+  pub fn write(&mut self, output_path: &str, data: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(output_path, data)?;
+
+    Ok(())
   }
 }
 
@@ -283,26 +805,178 @@ mod tests {
 
   macro_rules! items {
     ($($x:expr),*) => (vec![$($x.to_string()),*]);
-  }  
+  }
 
-  #[test]
-  fn it_build() {
-    let mut epub = EPUB::new(Info {
+  fn chapter(title: &str, paragraphs: Vec<String>, children: Vec<Chapter>) -> Chapter {
+    Chapter { title: title.to_string(), content: paragraphs, children }
+  }
+
+  fn sample_info(version: i8) -> Info {
+    Info {
       title: String::from("test"),
+      subtitle: None,
       description: String::from("test"),
       publisher: String::from("test"),
-      author: String::from("test"),
+      contributors: vec![Contributor { name: String::from("test"), role: ContributorRole::Author }],
+      identifiers: vec![],
       toc_title: String::from("test"),
       lang: String::from("en"),
-      fonts: vec![String::from("en")],
+      fonts: vec![],
       css: None,
-      version: 3
-    }, vec![items![
-      "Title",
+      version,
+      content_mode: ContentMode::PlainText,
+      cover: None,
+    }
+  }
+
+  #[test]
+  fn it_build() {
+    let mut epub = EPUB::new(sample_info(3), vec![chapter("Title", items![
       "Nullam tempor, metus vitae sagittis semper, massa nulla posuere ipsum, nec mollis tortor dui sed enim. Praesent ac orci posuere, iaculis elit at, eleifend lorem.",
       "Aliquam non posuere ex. Duis fermentum odio metus, quis ultrices nulla cursus vitae. Nullam blandit, nisi non posuere volutpat, lorem lorem aliquet ex, eu sagittis turpis felis nec dui. Integer iaculis arcu vitae elementum convallis. Pellentesque tempor, eros eu consectetur cursus, magna turpis lacinia nunc, ut pulvinar velit est non mauris. Nunc at erat purus. Morbi at arcu libero. Sed ac lobortis erat, id egestas tellus. Nullam velit turpis, maximus eget lacus quis, fringilla rhoncus odio. Praesent quam magna, maximus sed ullamcorper quis, dictum at turpis."
-    ]]);
-    
-    epub.run();
+    ], vec![])]);
+
+    epub.run("test.epub").unwrap();
+  }
+
+  #[test]
+  fn plain_text_mode_escapes_xml_special_characters() {
+    let mut info = sample_info(3);
+    info.title = String::from("Tom & Jerry <\"Classic\">");
+
+    let epub = EPUB::new(info, vec![chapter("Ch 1", items!["Rock & Roll"], vec![])]);
+    let chapters = epub.write_chapters();
+    let (_, xhtml) = &chapters[0];
+
+    assert!(xhtml.contains("Rock &amp; Roll"));
+    assert!(!xhtml.contains("Rock & Roll"));
+  }
+
+  #[test]
+  fn raw_html_mode_skips_escaping() {
+    let mut info = sample_info(3);
+    info.content_mode = ContentMode::RawHtml;
+
+    let epub = EPUB::new(info, vec![chapter("Ch 1", items!["<b>Rock & Roll</b>"], vec![])]);
+    let chapters = epub.write_chapters();
+    let (_, xhtml) = &chapters[0];
+
+    assert!(xhtml.contains("<b>Rock & Roll</b>"));
+  }
+
+  #[test]
+  fn version_2_emits_opf_2_package_without_nav_properties() {
+    let epub = EPUB::new(sample_info(2), vec![chapter("Ch 1", items!["text"], vec![])]);
+
+    assert!(epub.content_opf_v2(Uuid::new_v4()).contains("version=\"2.0\""));
+    assert!(!epub.manifest().contains("properties=\"nav\""));
+  }
+
+  #[test]
+  fn version_3_emits_opf_3_package_with_nav_properties() {
+    let epub = EPUB::new(sample_info(3), vec![chapter("Ch 1", items!["text"], vec![])]);
+
+    assert!(epub.content_opf_v3(Uuid::new_v4()).contains("version=\"3.0\""));
+    assert!(epub.manifest().contains("properties=\"nav\""));
+  }
+
+  #[test]
+  fn nested_chapters_get_sequential_play_order_and_correct_depth() {
+    let tree = vec![chapter("Part 1", items!["intro"], vec![
+      chapter("Chapter 1", items!["text"], vec![]),
+      chapter("Chapter 2", items!["text"], vec![]),
+    ])];
+
+    assert_eq!(chapters_depth(&tree), 2);
+
+    let epub = EPUB::new(sample_info(3), tree);
+    let toc = epub.toc_ncx();
+
+    assert!(toc.contains("playOrder=\"1\""));
+    assert!(toc.contains("playOrder=\"2\""));
+    assert!(toc.contains("playOrder=\"3\""));
+  }
+
+  #[test]
+  fn chapters_with_colliding_titles_get_disambiguated_slugs() {
+    let tree = vec![
+      chapter("Part One", items!["text"], vec![chapter("Introduction", items!["text"], vec![])]),
+      chapter("Part Two", items!["text"], vec![chapter("Introduction", items!["text"], vec![])]),
+    ];
+
+    let epub = EPUB::new(sample_info(3), tree);
+    let chapters = epub.write_chapters();
+    let slugs: Vec<&String> = chapters.iter().map(|(slug, _)| slug).collect();
+
+    let unique: std::collections::HashSet<&&String> = slugs.iter().collect();
+    assert_eq!(unique.len(), slugs.len());
+  }
+
+  #[test]
+  fn add_resource_gets_a_manifest_item() {
+    let mut epub = EPUB::new(sample_info(3), vec![chapter("Ch 1", items!["text"], vec![])]);
+    let href = epub.add_resource("cover.jpg", vec![0u8]);
+
+    assert_eq!(href, "images/cover.jpg");
+    assert!(epub.manifest().contains("<item id=\"images_cover_jpg\" href=\"images/cover.jpg\" media-type=\"image/jpeg\" />"));
+  }
+
+  #[test]
+  fn fonts_get_a_manifest_item_and_flip_on_specified_fonts() {
+    let mut info = sample_info(3);
+    info.fonts = vec![String::from("assets/MyFont.ttf")];
+
+    let epub = EPUB::new(info, vec![chapter("Ch 1", items!["text"], vec![])]);
+
+    assert!(epub.manifest().contains("<item id=\"fonts_myfont_ttf\" href=\"fonts/MyFont.ttf\" media-type=\"application/vnd.ms-opentype\" />"));
+    assert!(epub.content_opf_v3(Uuid::new_v4()).contains("property=\"ibooks:specified-fonts\">true<"));
+  }
+
+  #[test]
+  fn no_fonts_leaves_specified_fonts_false() {
+    let epub = EPUB::new(sample_info(3), vec![chapter("Ch 1", items!["text"], vec![])]);
+
+    assert!(epub.content_opf_v3(Uuid::new_v4()).contains("property=\"ibooks:specified-fonts\">false<"));
+  }
+
+  #[test]
+  fn cover_gets_a_manifest_item_and_spine_entry() {
+    let mut info = sample_info(3);
+    info.cover = Some(CoverImage::Bytes { file_name: String::from("cover.png"), data: vec![0u8] });
+
+    let epub = EPUB::new(info, vec![chapter("Ch 1", items!["text"], vec![])]);
+
+    assert!(epub.manifest().contains("<item id=\"cover-image\" href=\"images/cover.png\" media-type=\"image/png\" properties=\"cover-image\" />"));
+    assert!(epub.manifest().contains("<item id=\"cover\" href=\"cover.xhtml\" media-type=\"application/xhtml+xml\" />"));
+    assert!(epub.spine().contains("<itemref idref=\"cover\" linear=\"no\" />"));
+  }
+
+  #[test]
+  fn no_cover_omits_manifest_item_and_spine_entry() {
+    let epub = EPUB::new(sample_info(3), vec![chapter("Ch 1", items!["text"], vec![])]);
+
+    assert!(!epub.manifest().contains("cover-image"));
+    assert!(!epub.spine().contains("idref=\"cover\""));
+  }
+
+  #[test]
+  fn subtitle_contributor_and_identifier_metadata_is_rendered() {
+    let mut info = sample_info(3);
+    info.subtitle = Some(String::from("A Subtitle"));
+    info.contributors = vec![
+      Contributor { name: String::from("Author Name"), role: ContributorRole::Author },
+      Contributor { name: String::from("Editor Name"), role: ContributorRole::Editor },
+    ];
+    info.identifiers = vec![Identifier { scheme: IdentifierScheme::Isbn, value: String::from("978-0-00-000000-0") }];
+
+    let epub = EPUB::new(info, vec![chapter("Ch 1", items!["text"], vec![])]);
+    let opf = epub.content_opf_v3(Uuid::new_v4());
+
+    assert!(opf.contains("<dc:title id=\"subtitle\">A Subtitle</dc:title>"));
+    assert!(opf.contains("<dc:creator id=\"contributor-0\">Author Name</dc:creator>"));
+    assert!(opf.contains("<dc:contributor id=\"contributor-1\">Editor Name</dc:contributor>"));
+    assert!(opf.contains("property=\"role\" scheme=\"marc:relators\">edt</meta>"));
+    assert!(opf.contains("<dc:identifier id=\"BookId\">978-0-00-000000-0</dc:identifier>"));
+    assert!(opf.contains("property=\"identifier-type\">ISBN</meta>"));
   }
 }